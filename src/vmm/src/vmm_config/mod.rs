@@ -1,14 +1,17 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::fs::{File, OpenOptions};
 use std::io;
+use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::PathBuf;
 use std::sync::{Mutex, MutexGuard};
 
 use libc::O_NONBLOCK;
+use serde::{Deserialize, Deserializer};
 
 use rate_limiter::{RateLimiter, TokenBucket};
 
@@ -68,7 +71,8 @@ pub struct RateLimiterConfig {
 }
 
 impl RateLimiterConfig {
-    /// Updates the configuration, merging in new options from `new_config`.
+    /// Updates the configuration, merging in new options from `new_config`. A `None` field
+    /// in `new_config` leaves the corresponding bucket unchanged.
     pub fn update(&mut self, new_config: &RateLimiterConfig) {
         if new_config.bandwidth.is_some() {
             self.bandwidth = new_config.bandwidth;
@@ -77,6 +81,77 @@ impl RateLimiterConfig {
             self.ops = new_config.ops;
         }
     }
+
+    /// Updates the configuration from a PATCH payload, applying the tri-state `BucketUpdate`
+    /// carried by `new_config` to each bucket: `Keep` leaves it as-is, `Disable` clears it (the
+    /// resulting `RateLimiter` bucket becomes unlimited), and `Set` replaces it.
+    ///
+    /// This is additive alongside `update`: existing callers that PATCH with a full
+    /// `RateLimiterConfig` keep working unchanged; callers that need to express "disable this
+    /// bucket" use this method instead once their PATCH handler is wired up to send a
+    /// `RateLimiterUpdate`.
+    pub fn update_from_patch(&mut self, new_config: &RateLimiterUpdate) {
+        match new_config.bandwidth {
+            BucketUpdate::Keep => (),
+            BucketUpdate::Disable => self.bandwidth = None,
+            BucketUpdate::Set(config) => self.bandwidth = Some(config),
+        }
+        match new_config.ops {
+            BucketUpdate::Keep => (),
+            BucketUpdate::Disable => self.ops = None,
+            BucketUpdate::Set(config) => self.ops = Some(config),
+        }
+    }
+}
+
+/// A public-facing, stateless structure used to PATCH a live `RateLimiterConfig`. Unlike
+/// `RateLimiterConfig`, where a missing bucket always means "leave unchanged", this carries a
+/// `BucketUpdate` per bucket so a PATCH can also explicitly disable one.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimiterUpdate {
+    /// Update applied to the `bandwidth` bucket. Defaults to `Keep` when absent from the
+    /// PATCH payload.
+    #[serde(default)]
+    pub bandwidth: BucketUpdate,
+    /// Update applied to the `ops` bucket. Defaults to `Keep` when absent from the PATCH
+    /// payload.
+    #[serde(default)]
+    pub ops: BucketUpdate,
+}
+
+/// Describes the transition to apply to one of a `RateLimiter`'s token buckets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BucketUpdate {
+    /// The bucket is left unchanged.
+    Keep,
+    /// The bucket is cleared, making the corresponding `TokenType` unlimited.
+    Disable,
+    /// The bucket is replaced with the enclosed configuration.
+    Set(TokenBucketConfig),
+}
+
+impl Default for BucketUpdate {
+    fn default() -> Self {
+        BucketUpdate::Keep
+    }
+}
+
+impl<'de> Deserialize<'de> for BucketUpdate {
+    // A field that is present but `null` disables the bucket; a field carrying an object
+    // replaces it. A field missing from the PATCH payload never reaches this impl at all,
+    // since `#[serde(default)]` on `RateLimiterUpdate`'s fields resolves it to `Keep` first.
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<TokenBucketConfig>::deserialize(deserializer).map(|maybe_config| {
+            match maybe_config {
+                Some(config) => BucketUpdate::Set(config),
+                None => BucketUpdate::Disable,
+            }
+        })
+    }
 }
 
 impl TryInto<RateLimiter> for RateLimiterConfig {
@@ -96,49 +171,170 @@ impl TryInto<RateLimiter> for RateLimiterConfig {
     }
 }
 
+/// A public-facing, stateless structure describing an aggregate rate limiter shared by several
+/// queues of the same device (e.g. a multi-queue block or net device that should be throttled
+/// as a whole, rather than per-queue). The group is named by `id`; `RateLimiterConfig` has no
+/// field that refers to a group, so nothing in this module attaches a queue to a group.
+///
+/// The live counterpart would be a `rate_limiter::RateLimiterGroup` owning the two shared
+/// `TokenBucket`s and the worker thread that drives their `TimerFd`, handed out to each queue as
+/// a lightweight `rate_limiter::RateLimiterGroupHandle`. That subsystem lives in the
+/// `rate_limiter` crate, which is not part of this checkout, so only the config side is added
+/// here; there is deliberately no `TryInto` impl for it yet, since there is no live type, let
+/// alone constructor, for it to build.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimiterGroupConfig {
+    /// Unique identifier, referenced by the queues that share this group's budget.
+    pub id: String,
+    /// Data used to initialize the group's shared bandwidth bucket.
+    pub bandwidth: Option<TokenBucketConfig>,
+    /// Data used to initialize the group's shared ops bucket.
+    pub ops: Option<TokenBucketConfig>,
+}
+
 type Result<T> = std::result::Result<T, std::io::Error>;
 
+/// Default cap, in bytes, on how much unwritten output `Writer` stages before it starts
+/// dropping the oldest lines. Matches the 64K of unconsumed content a non-blocking FIFO
+/// itself would absorb before a plain write would start failing.
+pub const DEFAULT_WRITER_BUFFER_CAP: usize = 64 * 1024;
+
 /// Structure `Writer` used for writing to a FIFO.
 pub struct Writer {
-    line_writer: Mutex<io::LineWriter<File>>,
+    state: Mutex<WriterState>,
+}
+
+struct WriterState {
+    line_writer: io::LineWriter<File>,
+    /// Bytes that couldn't be written out last time because the reader isn't keeping up;
+    /// staged here instead of being dropped, and drained ahead of any new data.
+    pending: VecDeque<u8>,
+    /// Max number of bytes allowed in `pending` before the oldest whole lines get dropped.
+    pending_cap: usize,
+    /// Number of whole lines dropped so far because `pending` exceeded `pending_cap`.
+    missed_count: u64,
+}
+
+impl WriterState {
+    /// Drains previously staged bytes into the FIFO, stopping as soon as the reader applies
+    /// backpressure again.
+    fn drain_pending(&mut self) -> Result<()> {
+        while !self.pending.is_empty() {
+            // `make_contiguous` lets us hand the kernel a single slice without copying
+            // `pending` into a fresh `Vec` on every retry.
+            let chunk = self.pending.make_contiguous();
+            match self.line_writer.write(chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `msg`, staging whatever the FIFO can't currently absorb instead of dropping it.
+    fn write_or_stage(&mut self, msg: &[u8]) -> Result<()> {
+        self.drain_pending()?;
+
+        let mut remaining = msg;
+        if self.pending.is_empty() {
+            loop {
+                if remaining.is_empty() {
+                    return Ok(());
+                }
+                match self.line_writer.write(remaining) {
+                    Ok(0) => break,
+                    Ok(n) => remaining = &remaining[n..],
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        self.stage(remaining);
+        Ok(())
+    }
+
+    /// Stages `msg` for a later retry, dropping the oldest whole lines if that pushes
+    /// `pending` past `pending_cap`. Never drops a partial line: if what's left of `pending`
+    /// is a single in-flight line with no terminating `\n` yet, `pending_cap` is treated as a
+    /// soft limit and accumulation continues until that line completes.
+    fn stage(&mut self, msg: &[u8]) {
+        self.pending.extend(msg);
+        while self.pending.len() > self.pending_cap {
+            match self.pending.iter().position(|&b| b == b'\n') {
+                Some(newline_idx) => self.pending.drain(..=newline_idx),
+                None => break,
+            };
+            self.missed_count += 1;
+        }
+    }
 }
 
 impl Writer {
-    /// Create and open a FIFO for writing to it.
-    /// In order to not block the instance if nobody is consuming the message that is flushed to the
-    /// two pipes, we are opening it with `O_NONBLOCK` flag. In this case, writing to a pipe will
-    /// start failing when reaching 64K of unconsumed content. Simultaneously,
-    /// the `missed_metrics_count` metric will get increased.
+    /// Create and open a FIFO for writing to it, staging up to `DEFAULT_WRITER_BUFFER_CAP`
+    /// bytes of unwritten output. See `with_buffer_cap` for the staging behavior and for
+    /// picking a different cap.
     pub fn new(fifo_path: PathBuf) -> Result<Writer> {
+        Writer::with_buffer_cap(fifo_path, DEFAULT_WRITER_BUFFER_CAP)
+    }
+
+    /// Create and open a FIFO for writing to it, same as `new`, but with an explicit cap on
+    /// staged output.
+    ///
+    /// In order to not block the instance if nobody is consuming the message that is flushed to
+    /// the two pipes, we are opening it with `O_NONBLOCK` flag. Up to `buffer_cap_bytes` of
+    /// unwritten output is staged in memory and retried on the next write instead of being lost
+    /// to a stalled reader; once that cap is also exceeded, the oldest staged lines are dropped
+    /// and `missed_count` is incremented.
+    ///
+    /// This is additive alongside `new`: wiring `buffer_cap_bytes` into the logger/metrics
+    /// config structs so it's externally configurable is left to those modules, which aren't
+    /// part of this checkout.
+    pub fn with_buffer_cap(fifo_path: PathBuf, buffer_cap_bytes: usize) -> Result<Writer> {
         OpenOptions::new()
             .custom_flags(O_NONBLOCK)
             .read(true)
             .write(true)
             .open(&fifo_path)
             .map(|t| Writer {
-                line_writer: Mutex::new(io::LineWriter::new(t)),
+                state: Mutex::new(WriterState {
+                    line_writer: io::LineWriter::new(t),
+                    pending: VecDeque::new(),
+                    pending_cap: buffer_cap_bytes,
+                    missed_count: 0,
+                }),
             })
     }
 
-    fn get_line_writer(&self) -> MutexGuard<io::LineWriter<File>> {
-        match self.line_writer.lock() {
+    fn get_state(&self) -> MutexGuard<WriterState> {
+        match self.state.lock() {
             Ok(guard) => guard,
             // If a thread panics while holding this lock, the writer within should still be usable.
             // (we might get an incomplete log line or something like that).
             Err(poisoned) => poisoned.into_inner(),
         }
     }
+
+    /// Number of whole lines dropped so far because the staging buffer was full.
+    pub fn missed_count(&self) -> u64 {
+        self.get_state().missed_count
+    }
 }
 
 impl io::Write for Writer {
     fn write(&mut self, msg: &[u8]) -> Result<(usize)> {
-        let mut line_writer = self.get_line_writer();
-        line_writer.write_all(msg).map(|()| msg.len())
+        self.get_state().write_or_stage(msg).map(|()| msg.len())
     }
 
     fn flush(&mut self) -> Result<()> {
-        let mut line_writer = self.get_line_writer();
-        line_writer.flush()
+        let mut state = self.get_state();
+        state.drain_pending()?;
+        state.line_writer.flush()
     }
 }
 
@@ -188,11 +384,24 @@ mod tests {
 
         rlconf.update(&RateLimiterConfig {
             bandwidth: Some(TokenBucketConfig {
+                size: SIZE * 3,
+                one_time_burst: None,
+                refill_time: REFILL_TIME * 3,
+            }),
+            ops: None,
+        });
+        assert_eq!(rlconf.bandwidth.unwrap().size, SIZE * 3);
+        assert_eq!(rlconf.bandwidth.unwrap().refill_time, REFILL_TIME * 3);
+        // A `None` field in the patch leaves the existing bucket untouched.
+        assert_eq!(rlconf.ops.unwrap().size, SIZE * 2);
+
+        rlconf.update_from_patch(&RateLimiterUpdate {
+            bandwidth: BucketUpdate::Set(TokenBucketConfig {
                 size: SIZE * 2,
                 one_time_burst: Some(ONE_TIME_BURST * 2),
                 refill_time: REFILL_TIME * 2,
             }),
-            ops: None,
+            ops: BucketUpdate::Keep,
         });
         assert_eq!(rlconf.bandwidth.unwrap().size, SIZE * 2);
         assert_eq!(
@@ -203,6 +412,37 @@ mod tests {
         assert_eq!(rlconf.ops.unwrap().size, SIZE * 2);
         assert_eq!(rlconf.ops.unwrap().one_time_burst, None);
         assert_eq!(rlconf.ops.unwrap().refill_time, REFILL_TIME * 2);
+
+        rlconf.update_from_patch(&RateLimiterUpdate {
+            bandwidth: BucketUpdate::Disable,
+            ops: BucketUpdate::Keep,
+        });
+        assert!(rlconf.bandwidth.is_none());
+        assert!(rlconf.ops.is_some());
+    }
+
+    #[test]
+    fn test_rate_limiter_group_config() {
+        const SIZE: u64 = 1024 * 1024;
+        const ONE_TIME_BURST: u64 = 1024;
+        const REFILL_TIME: u64 = 1000;
+
+        let gconf = RateLimiterGroupConfig {
+            id: String::from("group0"),
+            bandwidth: Some(TokenBucketConfig {
+                size: SIZE,
+                one_time_burst: Some(ONE_TIME_BURST),
+                refill_time: REFILL_TIME,
+            }),
+            ops: None,
+        };
+        assert_eq!(gconf.id, "group0");
+        assert_eq!(gconf.bandwidth.unwrap().size, SIZE);
+        assert_eq!(gconf.bandwidth.unwrap().one_time_burst, Some(ONE_TIME_BURST));
+        assert_eq!(gconf.bandwidth.unwrap().refill_time, REFILL_TIME);
+        assert!(gconf.ops.is_none());
+        assert_eq!(gconf, gconf.clone());
+        assert_ne!(gconf, RateLimiterGroupConfig::default());
     }
 
     #[test]
@@ -217,5 +457,109 @@ mod tests {
         let msg = String::from("some message");
         assert!(fw.write(&msg.as_bytes()).is_ok());
         assert!(fw.flush().is_ok());
+        assert_eq!(fw.missed_count(), 0);
+    }
+
+    /// Creates a FIFO at `path` and opens a second, independent non-blocking read+write handle
+    /// on it (the same trick `Writer::new` uses to avoid ENXIO), so tests can fill and drain
+    /// the FIFO's kernel pipe buffer out from under a `Writer` to force real `EWOULDBLOCK`s.
+    fn fifo_and_probe(path: &std::path::Path) -> File {
+        std::fs::remove_file(path).expect("Failed to remove placeholder file.");
+        let cpath = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(cpath.as_ptr(), 0o600) }, 0);
+        OpenOptions::new()
+            .custom_flags(O_NONBLOCK)
+            .read(true)
+            .write(true)
+            .open(path)
+            .expect("Failed to open FIFO probe handle.")
+    }
+
+    /// Writes to `probe` until the FIFO's kernel pipe buffer is full, so the next write through
+    /// a `Writer` on the same FIFO is guaranteed to hit `EWOULDBLOCK` and get staged.
+    fn fill_pipe(probe: &mut File) {
+        let chunk = [0u8; 4096];
+        loop {
+            match probe.write(&chunk) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("unexpected error filling pipe: {}", e),
+            }
+        }
+    }
+
+    /// Reads everything currently available from `probe` without blocking.
+    fn drain_available(probe: &mut File) -> Vec<u8> {
+        use std::io::Read;
+
+        let mut out = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            match probe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => out.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("unexpected error draining pipe: {}", e),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_log_writer_stages_and_drains_on_backpressure() {
+        let log_file_temp =
+            TempFile::new().expect("Failed to create temporary output logging file.");
+        let path = log_file_temp.as_path().to_path_buf();
+        let mut probe = fifo_and_probe(&path);
+        fill_pipe(&mut probe);
+
+        let mut fw = Writer::new(path).unwrap();
+        let msg = b"staged message\n";
+        // The FIFO is full, so this write can't reach it at all and gets staged instead of
+        // dropped.
+        assert!(fw.write(msg).is_ok());
+        assert_eq!(fw.missed_count(), 0);
+
+        // Free up room in the FIFO, then let the writer drain what it staged.
+        drain_available(&mut probe);
+        assert!(fw.flush().is_ok());
+
+        let out = drain_available(&mut probe);
+        assert!(out.ends_with(msg));
+    }
+
+    #[test]
+    fn test_log_writer_drops_oldest_whole_line_but_keeps_partial_tail() {
+        let log_file_temp =
+            TempFile::new().expect("Failed to create temporary output logging file.");
+        let path = log_file_temp.as_path().to_path_buf();
+        let mut probe = fifo_and_probe(&path);
+        fill_pipe(&mut probe);
+
+        let mut fw = Writer::with_buffer_cap(path, 10).unwrap();
+
+        assert!(fw.write(b"aaaaa\n").is_ok());
+        assert_eq!(fw.missed_count(), 0);
+
+        // Pushes staged bytes past the 10-byte cap; the oldest whole line ("aaaaa\n") is
+        // dropped.
+        assert!(fw.write(b"bbbbb\n").is_ok());
+        assert_eq!(fw.missed_count(), 1);
+
+        // A partial (unterminated) line that alone exceeds the cap must never be dropped: the
+        // one full line ahead of it ("bbbbb\n") is dropped instead, and the partial tail is kept
+        // in full even though `pending` now exceeds `pending_cap`.
+        let partial = b"partial-line-longer-than-the-cap-with-no-trailing-newline";
+        assert!(fw.write(partial).is_ok());
+        assert_eq!(fw.missed_count(), 2);
+
+        drain_available(&mut probe);
+        assert!(fw.flush().is_ok());
+
+        let out = drain_available(&mut probe);
+        assert!(out.ends_with(partial));
+        assert!(!out.windows(5).any(|w| w == b"aaaaa"));
+        assert!(!out.windows(5).any(|w| w == b"bbbbb"));
     }
 }